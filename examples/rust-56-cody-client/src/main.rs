@@ -1,20 +1,248 @@
+use axum::extract::State;
+use axum::response::sse::{Event, Sse};
+use axum::response::IntoResponse;
+use axum::routing::post;
+use axum::{Json, Router};
 use futures::stream::StreamExt;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::env;
 use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, BufRead, Write};
+use std::net::SocketAddr;
 use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+mod session;
+use session::SessionStore;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "cody_chat")]
 struct Opt {
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+
     #[structopt(long = "context-repo", help = "Optional: Specify context repositories")]
     context_repo: Vec<String>,
 
-    #[structopt(long, required = true, help = "The message to send to Cody")]
+    #[structopt(long, help = "The message to send to Cody")]
+    message: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Start an interactive chat session that keeps conversation history"
+    )]
+    interactive: bool,
+
+    #[structopt(long, help = "Resume a previous session by id")]
+    resume: Option<String>,
+
+    #[structopt(
+        long,
+        default_value = "./cody.db",
+        help = "Path to the SQLite session database"
+    )]
+    db: String,
+
+    #[structopt(
+        long,
+        default_value = "3",
+        help = "Max attempts for transient HTTP failures and truncated streams"
+    )]
+    max_retries: u32,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Run a local HTTP server exposing POST /chat with SSE streaming
+    Serve {
+        #[structopt(long, default_value = "8787", help = "Port to listen on")]
+        port: u16,
+    },
+    /// Browse saved chat sessions
+    Sessions {
+        #[structopt(subcommand)]
+        action: SessionsCommand,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum SessionsCommand {
+    /// List all saved sessions
+    List,
+    /// Show the turns of a single session
+    Show { session_id: String },
+}
+
+/// A single turn in a Cody conversation, matching the `messages` entries the
+/// `/.api/completions/stream` endpoint expects.
+#[derive(Debug, Clone, Serialize)]
+struct Turn {
+    speaker: String,
+    text: String,
+}
+
+/// Shared state handed to every request the `serve` subcommand answers, so a
+/// single `Client` and `HeaderMap` are reused instead of rebuilt per call.
+struct AppState {
+    client: Client,
+    headers: HeaderMap,
+    graphql_url: String,
+    chat_completions_url: String,
+    max_retries: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatRequest {
     message: String,
+    #[serde(default)]
+    context_repos: Vec<String>,
+}
+
+/// Envelope every Sourcegraph GraphQL response is parsed into, so a
+/// malformed response or a populated `errors` array surfaces as an `Err`
+/// instead of panicking deep inside a `.unwrap()` on `data`.
+#[derive(Debug, Deserialize)]
+struct GraphResult<T> {
+    data: Option<T>,
+    #[serde(default)]
+    errors: Vec<GraphError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphError {
+    message: String,
+}
+
+impl<T> GraphResult<T> {
+    fn into_data(self) -> Result<T, Box<dyn Error>> {
+        if !self.errors.is_empty() {
+            let messages: Vec<&str> = self.errors.iter().map(|e| e.message.as_str()).collect();
+            return Err(format!("GraphQL errors: {}", messages.join("; ")).into());
+        }
+        self.data
+            .ok_or_else(|| "GraphQL response had no data and no errors".into())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RepositoriesData {
+    repositories: Repositories,
+}
+
+#[derive(Debug, Deserialize)]
+struct Repositories {
+    nodes: Vec<RepoNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RepoNode {
+    id: String,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodyContextData {
+    #[serde(rename = "getCodyContext")]
+    get_cody_context: Vec<CodyContextNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodyContextNode {
+    blob: CodyContextBlob,
+    #[serde(rename = "startLine")]
+    start_line: i64,
+    #[serde(rename = "endLine")]
+    end_line: i64,
+    #[serde(rename = "chunkContent")]
+    chunk_content: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodyContextBlob {
+    path: String,
+}
+
+/// Errors from talking to a Sourcegraph instance, distinguishing the cases
+/// callers may want to react to (retry, surface the server's own message)
+/// from a generic `Box<dyn Error>`.
+#[derive(Debug)]
+enum CodyError {
+    Http(reqwest::Error),
+    EarlyEof,
+    Protocol(String),
+    Api { status: StatusCode, body: String },
+}
+
+impl fmt::Display for CodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodyError::Http(err) => write!(f, "HTTP error: {}", err),
+            CodyError::EarlyEof => {
+                write!(f, "completion stream ended before a [DONE] sentinel")
+            }
+            CodyError::Protocol(message) => write!(f, "protocol error: {}", message),
+            CodyError::Api { status, body } => {
+                write!(f, "API error ({}): {}", status, body)
+            }
+        }
+    }
+}
+
+impl Error for CodyError {}
+
+impl From<reqwest::Error> for CodyError {
+    fn from(err: reqwest::Error) -> Self {
+        CodyError::Http(err)
+    }
+}
+
+impl CodyError {
+    /// Whether the failure looks transient enough to be worth retrying:
+    /// network-level errors, a stream truncated before `[DONE]`, and 5xx
+    /// responses. 4xx responses (bad token, bad request) are not retried.
+    fn is_retryable(&self) -> bool {
+        match self {
+            CodyError::Http(err) => !err.is_decode(),
+            CodyError::EarlyEof => true,
+            CodyError::Api { status, .. } => status.is_server_error(),
+            CodyError::Protocol(_) => false,
+        }
+    }
+}
+
+/// Runs `attempt` up to `max_attempts` times with exponential backoff,
+/// retrying only errors `CodyError::is_retryable` considers transient.
+async fn with_backoff<T, F, Fut>(max_attempts: u32, mut attempt: F) -> Result<T, CodyError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, CodyError>>,
+{
+    let max_attempts = max_attempts.max(1);
+    let mut delay = Duration::from_millis(250);
+
+    for attempt_num in 1..=max_attempts {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt_num < max_attempts && err.is_retryable() => {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    unreachable!("loop always returns by the last attempt")
 }
 
 #[tokio::main]
@@ -29,6 +257,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let opt = Opt::from_args();
 
+    if opt.cmd.is_none() && !opt.interactive && opt.message.is_none() {
+        eprintln!("error: --message is required unless --interactive or a subcommand is given");
+        Opt::clap().print_help().unwrap();
+        println!();
+        process::exit(1);
+    }
+
     let access_token = env::var("SRC_ACCESS_TOKEN")
         .expect("Error: SRC_ACCESS_TOKEN environment variable is not set.");
     let endpoint =
@@ -47,29 +282,333 @@ async fn main() -> Result<(), Box<dyn Error>> {
         HeaderValue::from_str(&format!("token {}", access_token))?,
     );
 
-    cody_chat(
-        &opt.context_repo,
-        &opt.message,
-        &graphql_url,
-        &chat_completions_url,
-        &headers,
-    )
-    .await?;
+    let client = Client::new();
+
+    match opt.cmd {
+        Some(Command::Serve { port }) => {
+            serve(
+                port,
+                graphql_url,
+                chat_completions_url,
+                headers,
+                opt.max_retries,
+            )
+            .await?;
+        }
+        Some(Command::Sessions { action }) => {
+            let store = SessionStore::open(&opt.db).await?;
+            run_sessions_command(&store, action).await?;
+        }
+        None if opt.interactive || opt.resume.is_some() => {
+            let store = SessionStore::open(&opt.db).await?;
+
+            let (session_id, history, repo_names) = match &opt.resume {
+                Some(id) => {
+                    if !store.session_exists(id).await? {
+                        return Err(format!("no saved session with id {}", id).into());
+                    }
+                    let history = store.load_turns(id).await?;
+                    let repo_names = store.load_context_repos(id).await?;
+                    (id.clone(), history, repo_names)
+                }
+                None => {
+                    let id = store.create_session(&opt.context_repo).await?;
+                    (id, Vec::new(), opt.context_repo.clone())
+                }
+            };
+
+            if opt.interactive {
+                interactive_chat(
+                    &client,
+                    &store,
+                    &session_id,
+                    &repo_names,
+                    history,
+                    &graphql_url,
+                    &chat_completions_url,
+                    &headers,
+                    opt.max_retries,
+                )
+                .await?;
+            } else {
+                cody_chat(
+                    &client,
+                    Some((&store, session_id.as_str())),
+                    &repo_names,
+                    opt.message.as_deref().unwrap_or_default(),
+                    history,
+                    &graphql_url,
+                    &chat_completions_url,
+                    &headers,
+                    opt.max_retries,
+                )
+                .await?;
+            }
+        }
+        None => {
+            // A plain one-shot `--message` run with no --interactive and no
+            // --resume has no session to persist to or resume from, so skip
+            // opening a database at all rather than littering a ./cody.db on
+            // every invocation.
+            cody_chat(
+                &client,
+                None,
+                &opt.context_repo,
+                opt.message.as_deref().unwrap_or_default(),
+                Vec::new(),
+                &graphql_url,
+                &chat_completions_url,
+                &headers,
+                opt.max_retries,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_sessions_command(
+    store: &SessionStore,
+    action: SessionsCommand,
+) -> Result<(), Box<dyn Error>> {
+    match action {
+        SessionsCommand::List => {
+            for session in store.list_sessions().await? {
+                println!(
+                    "{}  {} turns  repos={:?}  created={}",
+                    session.id, session.turn_count, session.context_repos, session.created_at
+                );
+            }
+        }
+        SessionsCommand::Show { session_id } => {
+            for turn in store.load_turns(&session_id).await? {
+                println!("{}: {}", turn.speaker, turn.text);
+            }
+        }
+    }
 
     Ok(())
 }
 
+/// Sends a single one-shot message and prints Cody's answer. `session` is
+/// `Some` only when the caller is persisting (i.e. `--resume` was given),
+/// since a plain `--message` run has nothing to resume and shouldn't create
+/// a session just to immediately discard it.
 async fn cody_chat(
+    client: &Client,
+    session: Option<(&SessionStore, &str)>,
     repo_names: &[String],
     query: &str,
+    mut history: Vec<Turn>,
+    graphql_url: &str,
+    chat_completions_url: &str,
+    headers: &HeaderMap,
+    max_retries: u32,
+) -> Result<(), Box<dyn Error>> {
+    let (final_prompt, repo_ids) =
+        build_prompt(client, repo_names, query, graphql_url, headers, max_retries).await?;
+    let human_turn = Turn {
+        speaker: "human".to_string(),
+        text: final_prompt,
+    };
+    if let Some((store, session_id)) = session {
+        store.append_turn(session_id, &human_turn).await?;
+        store.save_repo_ids(session_id, &repo_ids).await?;
+    }
+    history.push(human_turn);
+
+    let response =
+        chat_completions(client, &history, chat_completions_url, headers, max_retries).await?;
+
+    if let Some((store, session_id)) = session {
+        let assistant_turn = Turn {
+            speaker: "assistant".to_string(),
+            text: response,
+        };
+        store.append_turn(session_id, &assistant_turn).await?;
+    }
+
+    Ok(())
+}
+
+/// Runs a REPL that keeps the full turn history and resends it on every
+/// prompt, so Cody sees the whole conversation rather than a single message.
+/// Reads from stdin until EOF or a `/quit` command. Every turn is persisted
+/// to `store` as it's produced, so the session can be resumed later.
+async fn interactive_chat(
+    client: &Client,
+    store: &SessionStore,
+    session_id: &str,
+    repo_names: &[String],
+    mut history: Vec<Turn>,
     graphql_url: &str,
     chat_completions_url: &str,
     headers: &HeaderMap,
+    max_retries: u32,
 ) -> Result<(), Box<dyn Error>> {
-    let final_prompt = if !repo_names.is_empty() {
-        let context = get_repo_context(repo_names, query, graphql_url, headers).await?;
-        format!(
-            r#"
+    let stdin = io::stdin();
+
+    loop {
+        print!("> ");
+        io::stdout().flush().ok();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "/quit" {
+            break;
+        }
+
+        let (final_prompt, repo_ids) =
+            build_prompt(client, repo_names, line, graphql_url, headers, max_retries).await?;
+        let human_turn = Turn {
+            speaker: "human".to_string(),
+            text: final_prompt,
+        };
+        store.append_turn(session_id, &human_turn).await?;
+        store.save_repo_ids(session_id, &repo_ids).await?;
+        history.push(human_turn);
+
+        let response =
+            chat_completions(client, &history, chat_completions_url, headers, max_retries).await?;
+        println!();
+
+        let assistant_turn = Turn {
+            speaker: "assistant".to_string(),
+            text: response,
+        };
+        store.append_turn(session_id, &assistant_turn).await?;
+        history.push(assistant_turn);
+    }
+
+    Ok(())
+}
+
+/// Starts the `serve` subcommand's HTTP service: a single long-running
+/// process exposing `POST /chat`, reusing one `Client`/`HeaderMap` pair for
+/// every request it answers instead of building them per request.
+async fn serve(
+    port: u16,
+    graphql_url: String,
+    chat_completions_url: String,
+    headers: HeaderMap,
+    max_retries: u32,
+) -> Result<(), Box<dyn Error>> {
+    let state = Arc::new(AppState {
+        client: Client::new(),
+        headers,
+        graphql_url,
+        chat_completions_url,
+        max_retries,
+    });
+
+    let app = Router::new()
+        .route("/chat", post(handle_chat))
+        .with_state(state);
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    println!("cody_chat serve listening on http://{}", addr);
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+/// `POST /chat` handler: resolves repo context exactly like the CLI path,
+/// then re-streams each Cody completion delta to the caller as an SSE event.
+async fn handle_chat(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ChatRequest>,
+) -> impl IntoResponse {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Result<Event, Infallible>>();
+
+    tokio::spawn(async move {
+        let final_prompt = match build_prompt(
+            &state.client,
+            &req.context_repos,
+            &req.message,
+            &state.graphql_url,
+            &state.headers,
+            state.max_retries,
+        )
+        .await
+        {
+            Ok((prompt, _repo_ids)) => prompt,
+            Err(err) => {
+                tx.send(Ok(Event::default().event("error").data(err.to_string())))
+                    .ok();
+                return;
+            }
+        };
+
+        let messages = vec![Turn {
+            speaker: "human".to_string(),
+            text: final_prompt,
+        }];
+
+        // Tracks what's actually been sent to the SSE client across retry
+        // attempts, same as chat_completions' `printed` tracker: stream_completion's
+        // own `previous` resets to "" on every attempt, so diffing against it
+        // directly would resend deltas the client already received after an
+        // `EarlyEof` retry.
+        let mut sent = String::new();
+        let result = with_backoff(state.max_retries, || {
+            stream_completion(
+                &state.client,
+                &messages,
+                &state.chat_completions_url,
+                &state.headers,
+                |_previous, current| {
+                    match diff_delta(&sent, current) {
+                        Delta::Append(text) => {
+                            tx.send(Ok(Event::default().data(text.to_string()))).ok();
+                        }
+                        Delta::Reset(text) => {
+                            tx.send(Ok(Event::default()
+                                .event("reset")
+                                .data(text.to_string())))
+                                .ok();
+                        }
+                    }
+                    sent = current.to_string();
+                },
+            )
+        })
+        .await;
+
+        if let Err(err) = result {
+            tx.send(Ok(Event::default().event("error").data(err.to_string())))
+                .ok();
+        }
+    });
+
+    Sse::new(UnboundedReceiverStream::new(rx))
+}
+
+/// Builds the human preamble for a single query, splicing in freshly
+/// retrieved repo context when `repo_names` is non-empty, and returns the
+/// repo names resolved to IDs along with it so callers can persist them.
+async fn build_prompt(
+    client: &Client,
+    repo_names: &[String],
+    query: &str,
+    graphql_url: &str,
+    headers: &HeaderMap,
+    max_retries: u32,
+) -> Result<(String, HashMap<String, String>), CodyError> {
+    if !repo_names.is_empty() {
+        let (context, repo_ids) =
+            get_repo_context(client, repo_names, query, graphql_url, headers, max_retries).await?;
+        Ok((
+            format!(
+                r#"
         You are a helpful assistant.
         You are given the following context:
         {}
@@ -77,37 +616,39 @@ async fn cody_chat(
         {}
         You need to answer the query based on the context.
         "#,
-            context, query
-        )
+                context, query
+            ),
+            repo_ids,
+        ))
     } else {
-        format!(
-            r#"
+        Ok((
+            format!(
+                r#"
         You are a helpful assistant.
         You are given the following query:
         {}
         Please provide an answer to the query.
         "#,
-            query
-        )
-    };
-
-    let response = chat_completions(&final_prompt, chat_completions_url, headers).await?;
-    println!("{}", response);
-
-    Ok(())
+                query
+            ),
+            HashMap::new(),
+        ))
+    }
 }
 
 async fn get_repo_context(
+    client: &Client,
     repo_names: &[String],
     query: &str,
     graphql_url: &str,
     headers: &HeaderMap,
-) -> Result<String, Box<dyn Error>> {
+    max_retries: u32,
+) -> Result<(String, HashMap<String, String>), CodyError> {
     if repo_names.is_empty() {
-        return Ok(String::new());
+        return Ok((String::new(), HashMap::new()));
     }
 
-    let repo_ids = get_repo_ids(repo_names, graphql_url, headers).await?;
+    let repo_ids = get_repo_ids(client, repo_names, graphql_url, headers, max_retries).await?;
 
     let context_search_query = r#"
     query GetCodyContext($repos: [ID!]!, $query: String!, $codeResultsCount: Int!, $textResultsCount: Int!) {
@@ -139,37 +680,29 @@ async fn get_repo_context(
         "textResultsCount": 5,
     });
 
-    let client = Client::new();
-    let response = client
-        .post(graphql_url)
-        .headers(headers.clone())
-        .json(&json!({
-            "query": context_search_query,
-            "variables": variables,
-        }))
-        .send()
-        .await?;
+    let data: CodyContextData = post_graphql(
+        client,
+        graphql_url,
+        headers,
+        context_search_query,
+        variables,
+        max_retries,
+    )
+    .await?;
 
-    if response.status().is_success() {
-        let data: Value = response.json().await?;
-        let context = data["data"]["getCodyContext"].as_array().unwrap();
-        Ok(format_context(context))
-    } else {
-        println!("Request failed with status code: {}", response.status());
-        Ok(String::new())
-    }
+    Ok((format_context(&data.get_cody_context), repo_ids))
 }
 
-fn format_context(context: &[Value]) -> String {
+fn format_context(context: &[CodyContextNode]) -> String {
     let mut context_parts = vec!["<context>".to_string()];
 
     for result in context {
         context_parts.push("<item>".to_string());
         context_parts.push(format!(
             "<file>{}:{}-{}</file>",
-            result["blob"]["path"], result["startLine"], result["endLine"]
+            result.blob.path, result.start_line, result.end_line
         ));
-        context_parts.push(format!("<chunk>{}</chunk>", result["chunkContent"]));
+        context_parts.push(format!("<chunk>{}</chunk>", result.chunk_content));
         context_parts.push("</item>".to_string());
     }
 
@@ -178,10 +711,12 @@ fn format_context(context: &[Value]) -> String {
 }
 
 async fn get_repo_ids(
+    client: &Client,
     repo_names: &[String],
     graphql_url: &str,
     headers: &HeaderMap,
-) -> Result<serde_json::Map<String, Value>, Box<dyn Error>> {
+    max_retries: u32,
+) -> Result<HashMap<String, String>, CodyError> {
     let repository_ids_query = r#"
     query Repositories($names: [String!]!, $first: Int!) {
         repositories(names: $names, first: $first) {
@@ -198,46 +733,81 @@ async fn get_repo_ids(
         "first": repo_names.len(),
     });
 
-    let client = Client::new();
-    let response = client
-        .post(graphql_url)
-        .headers(headers.clone())
-        .json(&json!({
-            "query": repository_ids_query,
-            "variables": variables,
-        }))
-        .send()
-        .await?;
+    let data: RepositoriesData = post_graphql(
+        client,
+        graphql_url,
+        headers,
+        repository_ids_query,
+        variables,
+        max_retries,
+    )
+    .await?;
 
-    if response.status().is_success() {
-        let data: Value = response.json().await?;
-        let nodes = data["data"]["repositories"]["nodes"].as_array().unwrap();
-        Ok(nodes
-            .iter()
-            .map(|node| {
-                (
-                    node["name"].as_str().unwrap().to_string(),
-                    node["id"].clone(),
-                )
-            })
-            .collect())
-    } else {
-        println!(
-            "Failed to fetch repository IDs. Status code: {}",
-            response.status()
-        );
-        Ok(serde_json::Map::new())
-    }
+    Ok(data
+        .repositories
+        .nodes
+        .into_iter()
+        .map(|node| (node.name, node.id))
+        .collect())
 }
 
-async fn chat_completions(
+/// Posts one GraphQL `query`/`variables` pair, retrying transient failures,
+/// and deserializes the response into `GraphResult<T>` so a non-2xx status
+/// or a populated `errors` array surfaces as a `CodyError` with the server's
+/// own message instead of a panic or a silently-swallowed failure.
+async fn post_graphql<T: DeserializeOwned>(
+    client: &Client,
+    url: &str,
+    headers: &HeaderMap,
     query: &str,
+    variables: Value,
+    max_retries: u32,
+) -> Result<T, CodyError> {
+    with_backoff(max_retries, || async {
+        let response = client
+            .post(url)
+            .headers(headers.clone())
+            .json(&json!({
+                "query": query,
+                "variables": variables,
+            }))
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(CodyError::Api { status, body });
+        }
+
+        let result: GraphResult<T> = response.json().await?;
+        result
+            .into_data()
+            .map_err(|err| CodyError::Protocol(err.to_string()))
+    })
+    .await
+}
+
+/// Drives a single attempt at the SSE completion stream, invoking
+/// `on_completion(previous, current)` with the previously- and newly-seen
+/// cumulative `completion` text for every event, and returning the final
+/// cumulative string. Returns `CodyError::EarlyEof` if the byte stream ends
+/// without a `[DONE]` sentinel, e.g. a dropped connection mid-response.
+/// Shared by the CLI's stdout printer and the `serve` subcommand's SSE
+/// re-streaming; neither retries here, they each wrap this in `with_backoff`.
+async fn stream_completion<F>(
+    client: &Client,
+    messages: &[Turn],
     chat_completions_url: &str,
     headers: &HeaderMap,
-) -> Result<String, Box<dyn Error>> {
+    mut on_completion: F,
+) -> Result<String, CodyError>
+where
+    F: FnMut(&str, &str),
+{
     let data = json!({
         "maxTokensToSample": 4000,
-        "messages": [{"speaker": "human", "text": query}],
+        "messages": messages,
         "model": "gpt-4o",
         "temperature": 0.2,
         "topK": -1,
@@ -245,36 +815,208 @@ async fn chat_completions(
         "stream": true,
     });
 
-    let client = Client::new();
-    let mut response = client
+    let response = client
         .post(chat_completions_url)
         .headers(headers.clone())
         .json(&data)
         .send()
-        .await?
-        .bytes_stream();
+        .await?;
 
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(CodyError::Api { status, body });
+    }
+
+    let mut bytes = response.bytes_stream();
     let mut last_response = String::new();
     let mut buffer = String::new();
+    let mut saw_done = false;
 
-    while let Some(chunk) = response.next().await {
+    while let Some(chunk) = bytes.next().await {
         let chunk = chunk?;
         buffer.push_str(&String::from_utf8_lossy(&chunk));
 
         while let Some(pos) = buffer.find('\n') {
             let line = buffer.drain(..=pos).collect::<String>();
             if line.starts_with("data: ") {
-                let data = line.trim_start_matches("data: ");
-                if data != "[DONE]" {
-                    if let Ok(event_data) = serde_json::from_str::<Value>(data) {
-                        if let Some(completion) = event_data["completion"].as_str() {
-                            last_response = completion.to_string();
-                        }
+                let data = line.trim_start_matches("data: ").trim_end();
+                if data == "[DONE]" {
+                    saw_done = true;
+                } else if let Ok(event_data) = serde_json::from_str::<Value>(data) {
+                    if let Some(completion) = event_data["completion"].as_str() {
+                        on_completion(&last_response, completion);
+                        last_response = completion.to_string();
                     }
                 }
             }
         }
     }
 
+    if !saw_done {
+        return Err(CodyError::EarlyEof);
+    }
+
+    Ok(last_response)
+}
+
+async fn chat_completions(
+    client: &Client,
+    messages: &[Turn],
+    chat_completions_url: &str,
+    headers: &HeaderMap,
+    max_retries: u32,
+) -> Result<String, CodyError> {
+    let stdout = std::io::stdout();
+    // Tracks what's actually on the terminal across retry attempts.
+    // stream_completion's own `previous` resets to "" on every attempt, so
+    // diffing against it directly would re-append the whole completion after
+    // whatever a failed attempt already printed; diff against this instead.
+    let mut printed = String::new();
+
+    let last_response = with_backoff(max_retries, || {
+        stream_completion(
+            client,
+            messages,
+            chat_completions_url,
+            headers,
+            |_previous, current| {
+                print_delta(&printed, current, &stdout);
+                printed = current.to_string();
+            },
+        )
+    })
+    .await?;
+
+    println!();
+
     Ok(last_response)
 }
+
+enum Delta<'a> {
+    Append(&'a str),
+    Reset(&'a str),
+}
+
+/// Compares the previously-printed cumulative text against the newly
+/// received one: the normal case is an appended suffix, but a regeneration
+/// can make the new text shorter or diverge, in which case the caller should
+/// discard what it printed and start over.
+fn diff_delta<'a>(previous: &str, current: &'a str) -> Delta<'a> {
+    if current.starts_with(previous) {
+        Delta::Append(&current[previous.len()..])
+    } else {
+        Delta::Reset(current)
+    }
+}
+
+/// Writes only the newly appended text since `previous` to stdout, flushing
+/// after each write so the terminal shows a live typing effect. If `current`
+/// is not a continuation of `previous` (e.g. Cody regenerated the answer and
+/// the new completion is shorter or diverges), the previous output is
+/// cleared with ANSI escapes (so multi-line completions are cleared in
+/// full, not just their last line) and the full new text is reprinted.
+fn print_delta(previous: &str, current: &str, mut stdout: impl Write) {
+    match diff_delta(previous, current) {
+        Delta::Append(text) => {
+            write!(stdout, "{}", text).ok();
+        }
+        Delta::Reset(text) => {
+            clear_printed(previous, &mut stdout);
+            write!(stdout, "{}", text).ok();
+        }
+    }
+    stdout.flush().ok();
+}
+
+/// Moves the cursor back to the start of the first line `previous` was
+/// printed on, then erases everything from there to the end of the
+/// terminal, so a regenerated completion that's shorter or spans fewer
+/// lines doesn't leave stale text from the discarded one behind.
+fn clear_printed(previous: &str, mut stdout: impl Write) {
+    let lines_up = previous.matches('\n').count();
+    if lines_up > 0 {
+        write!(stdout, "\x1B[{}A", lines_up).ok();
+    }
+    write!(stdout, "\r\x1B[0J").ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_delta_appends_when_current_extends_previous() {
+        match diff_delta("Hello", "Hello, world") {
+            Delta::Append(text) => assert_eq!(text, ", world"),
+            Delta::Reset(_) => panic!("expected Append"),
+        }
+    }
+
+    #[test]
+    fn diff_delta_resets_when_current_diverges() {
+        match diff_delta("Hello, world", "Goodbye") {
+            Delta::Reset(text) => assert_eq!(text, "Goodbye"),
+            Delta::Append(_) => panic!("expected Reset"),
+        }
+    }
+
+    #[test]
+    fn diff_delta_resets_when_current_is_a_shorter_regeneration() {
+        match diff_delta("Hello, world", "Hello") {
+            Delta::Reset(text) => assert_eq!(text, "Hello"),
+            Delta::Append(_) => panic!("expected Reset"),
+        }
+    }
+
+    #[tokio::test]
+    async fn with_backoff_retries_retryable_errors_then_succeeds() {
+        let mut attempts = 0;
+        let result = with_backoff(3, || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt < 3 {
+                    Err(CodyError::EarlyEof)
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn with_backoff_gives_up_after_max_attempts() {
+        let mut attempts = 0;
+        let result = with_backoff(2, || {
+            attempts += 1;
+            async { Err::<(), _>(CodyError::EarlyEof) }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn with_backoff_does_not_retry_non_retryable_errors() {
+        let mut attempts = 0;
+        let result = with_backoff(5, || {
+            attempts += 1;
+            async {
+                Err::<(), _>(CodyError::Api {
+                    status: StatusCode::BAD_REQUEST,
+                    body: String::new(),
+                })
+            }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
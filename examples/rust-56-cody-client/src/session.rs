@@ -0,0 +1,187 @@
+use crate::Turn;
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::{Row, SqlitePool};
+use std::collections::HashMap;
+use std::error::Error;
+use uuid::Uuid;
+
+/// A SQLite-backed store for chat sessions, so `--resume <id>` can reload a
+/// prior conversation's turns and `sessions list`/`sessions show` can browse
+/// history across process runs.
+pub struct SessionStore {
+    pool: SqlitePool,
+}
+
+/// One row of `sessions list` output.
+pub struct SessionSummary {
+    pub id: String,
+    pub context_repos: Vec<String>,
+    pub created_at: String,
+    pub turn_count: i64,
+}
+
+impl SessionStore {
+    /// Opens (creating if necessary) the SQLite database at `db_path` and
+    /// ensures the `sessions`/`messages` schema exists.
+    pub async fn open(db_path: &str) -> Result<Self, Box<dyn Error>> {
+        let pool = SqlitePoolOptions::new()
+            .connect(&format!("sqlite://{}?mode=rwc", db_path))
+            .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                context_repos TEXT NOT NULL,
+                repo_ids TEXT NOT NULL DEFAULT '{}',
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS messages (
+                session_id TEXT NOT NULL REFERENCES sessions(id),
+                idx INTEGER NOT NULL,
+                speaker TEXT NOT NULL,
+                text TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                PRIMARY KEY (session_id, idx)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(Self { pool })
+    }
+
+    /// Starts a new session for the given `--context-repo` list and returns
+    /// its generated id.
+    pub async fn create_session(&self, context_repos: &[String]) -> Result<String, Box<dyn Error>> {
+        let id = Uuid::new_v4().to_string();
+        let context_repos_json = serde_json::to_string(context_repos)?;
+
+        sqlx::query("INSERT INTO sessions (id, context_repos) VALUES (?, ?)")
+            .bind(&id)
+            .bind(&context_repos_json)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(id)
+    }
+
+    /// Returns whether a session with the given id exists, so callers can
+    /// reject an unknown `--resume` id up front instead of silently
+    /// inserting turns under a session row that was never created.
+    pub async fn session_exists(&self, session_id: &str) -> Result<bool, Box<dyn Error>> {
+        let row = sqlx::query("SELECT 1 FROM sessions WHERE id = ?")
+            .bind(session_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    /// Records the repo names resolved to IDs for this session's most recent
+    /// turn, so the session row records what Cody was actually grounded
+    /// against. `sessions show` prints only the turns, not these IDs.
+    pub async fn save_repo_ids(
+        &self,
+        session_id: &str,
+        repo_ids: &HashMap<String, String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let repo_ids_json = serde_json::to_string(repo_ids)?;
+
+        sqlx::query("UPDATE sessions SET repo_ids = ?, updated_at = datetime('now') WHERE id = ?")
+            .bind(&repo_ids_json)
+            .bind(session_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Appends one turn to the session's history at the next available index.
+    pub async fn append_turn(&self, session_id: &str, turn: &Turn) -> Result<(), Box<dyn Error>> {
+        let next_idx: i64 = sqlx::query("SELECT COALESCE(MAX(idx), -1) + 1 FROM messages WHERE session_id = ?")
+            .bind(session_id)
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)?;
+
+        sqlx::query("INSERT INTO messages (session_id, idx, speaker, text) VALUES (?, ?, ?, ?)")
+            .bind(session_id)
+            .bind(next_idx)
+            .bind(&turn.speaker)
+            .bind(&turn.text)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query("UPDATE sessions SET updated_at = datetime('now') WHERE id = ?")
+            .bind(session_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Loads a session's turns in order, for splicing into the `messages`
+    /// array before sending a new prompt.
+    pub async fn load_turns(&self, session_id: &str) -> Result<Vec<Turn>, Box<dyn Error>> {
+        let rows = sqlx::query("SELECT speaker, text FROM messages WHERE session_id = ? ORDER BY idx")
+            .bind(session_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(Turn {
+                    speaker: row.try_get("speaker")?,
+                    text: row.try_get("text")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Loads the `--context-repo` list a session was created with, so
+    /// `--resume` doesn't require re-specifying it.
+    pub async fn load_context_repos(&self, session_id: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let row = sqlx::query("SELECT context_repos FROM sessions WHERE id = ?")
+            .bind(session_id)
+            .fetch_one(&self.pool)
+            .await?;
+        let context_repos_json: String = row.try_get("context_repos")?;
+        Ok(serde_json::from_str(&context_repos_json)?)
+    }
+
+    /// Lists every saved session, newest first.
+    pub async fn list_sessions(&self) -> Result<Vec<SessionSummary>, Box<dyn Error>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT s.id, s.context_repos, s.created_at, COUNT(m.idx) AS turn_count
+            FROM sessions s
+            LEFT JOIN messages m ON m.session_id = s.id
+            GROUP BY s.id
+            ORDER BY s.created_at DESC
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let context_repos_json: String = row.try_get("context_repos")?;
+                Ok(SessionSummary {
+                    id: row.try_get("id")?,
+                    context_repos: serde_json::from_str(&context_repos_json)?,
+                    created_at: row.try_get("created_at")?,
+                    turn_count: row.try_get("turn_count")?,
+                })
+            })
+            .collect()
+    }
+}